@@ -4,6 +4,8 @@ use alloc::{collections::BTreeMap, string::String, vec::Vec};
 use core::hash::{Hash, Hasher};
 use std::collections::hash_map::DefaultHasher;
 
+use serde::{Deserialize, Serialize};
+
 lazy_static::lazy_static! {
     /// The ln table with value ln(x)<<44 for x in [0,65536).
     static ref LN_TABLE: Vec<u64> =
@@ -11,17 +13,103 @@ lazy_static::lazy_static! {
 }
 
 /// The CRUSH algorithm.
-#[derive(Default, Clone)]
+#[derive(Default, Clone, Serialize, Deserialize)]
 pub struct Crush {
     root: Node,
+    /// Operations that have been staged but not yet committed to `root`.
+    /// Never persisted: a deserialized map is always fully committed.
+    #[serde(skip)]
+    staged: Vec<StagedOp>,
+    /// Bumped every time `commit` is called.
+    version: u64,
+    /// When set by `assign_balanced`, `locate_all` consults this
+    /// pgid -> osd-paths table instead of the hash-based `select`.
+    balanced_table: Option<BTreeMap<u32, Vec<String>>>,
+    /// Source of the next `Node::id`, so ids stay unique and stable even as
+    /// nodes are renamed or moved.
+    next_id: u64,
+}
+
+/// A pending edit to the cluster map, queued by `stage_add_weight`/`stage_set_inout`
+/// until `commit` or `revert` is called.
+#[derive(Clone)]
+enum StagedOp {
+    AddWeight { path: String, weight: i64 },
+    SetInout { path: String, out: bool },
+}
+
+/// The outcome of `Crush::preview`: how many placement groups would relocate
+/// if the currently staged operations were committed.
+#[derive(Debug, Clone, Default)]
+pub struct MovementReport {
+    /// Percentage of the previewed placement groups that would relocate.
+    pub percent_moved: f64,
+    /// The pgids that would relocate.
+    pub moved: Vec<u32>,
+}
+
+/// Errors returned by the failure-domain-aware selection methods.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CrushError {
+    /// The topology doesn't have enough distinct `domain_type` ancestors to
+    /// satisfy the requested redundancy.
+    InsufficientRedundancy {
+        domain_type: String,
+        requested: u32,
+        available: u32,
+    },
+    /// `rename`/`move_node` would overwrite an existing node at `path`.
+    NameConflict { path: String },
+    /// `domain_type` doesn't match any level observed while walking the tree.
+    UnknownDomainType { domain_type: String },
+    /// `move_node` was asked to move `path` into `new_parent`, but
+    /// `new_parent` is `path` itself or one of its own descendants.
+    InvalidMove { path: String, new_parent: String },
+}
+
+impl core::fmt::Display for CrushError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CrushError::InsufficientRedundancy {
+                domain_type,
+                requested,
+                available,
+            } => write!(
+                f,
+                "requested {} replicas spread across distinct {} domains, but only {} are available",
+                requested, domain_type, available
+            ),
+            CrushError::NameConflict { path } => {
+                write!(f, "a node already exists at {}", path)
+            }
+            CrushError::UnknownDomainType { domain_type } => {
+                write!(f, "no node of type {} was found in the tree", domain_type)
+            }
+            CrushError::InvalidMove { path, new_parent } => write!(
+                f,
+                "cannot move {} into {}: the destination is the node itself or one of its descendants",
+                path, new_parent
+            ),
+        }
+    }
 }
 
+impl std::error::Error for CrushError {}
+
 /// A node in cluster map.
 ///
 /// Maybe root / row / rack / host / osd.
-#[derive(Default, Clone)]
+#[derive(Default, Clone, Serialize, Deserialize)]
 struct Node {
+    /// Opaque, persistent identity assigned at insertion. Used as the straw2
+    /// hashing input instead of the path component, so renaming a node or
+    /// moving it to a new parent doesn't reshuffle placement.
+    id: u64,
+    /// Capacity in bytes for a leaf (`osd`), or the sum of its children's
+    /// weight otherwise. Also used, unitlessly, as the straw2 hashing weight.
     weight: u64,
+    /// Bytes used on a leaf. Always 0 on non-leaf nodes.
+    used: u64,
     out: bool,
     _type: String,
     children: BTreeMap<String, Node>,
@@ -40,7 +128,7 @@ fn find_next_power_of_2(n: u32) -> u32 {
 impl Crush {
     /// Add weight to a node.
     pub fn add_weight(&mut self, path: &str, weight: i64) {
-        self.root.add_weight(path, weight);
+        self.root.add_weight(path, weight, &mut self.next_id);
     }
 
     /// Locate a node by `pgid`.
@@ -76,6 +164,12 @@ impl Crush {
     }
 
     pub fn locate_all(&self, pgid: u32, replicas: u32) -> Vec<String> {
+        if let Some(table) = self.balanced_table.as_ref().and_then(|t| t.get(&pgid)) {
+            let mut paths = table.clone();
+            paths.sort();
+            return paths;
+        }
+
         let mut paths = self.select(pgid, replicas, "");
         for p in paths.iter_mut() {
             while !p.contains("osd") {
@@ -109,10 +203,302 @@ impl Crush {
         self.root.get(path).out
     }
 
+    /// Rename a node in place, keeping its `id` (and therefore its
+    /// placements) unchanged. Fails rather than clobbering an existing
+    /// sibling already using `new_name`.
+    pub fn rename(&mut self, path: &str, new_name: &str) -> Result<(), CrushError> {
+        let (parent_path, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let parent = self.root.get_mut(parent_path);
+        if parent.children.contains_key(new_name) {
+            return Err(CrushError::NameConflict {
+                path: join_path(parent_path, new_name),
+            });
+        }
+        if let Some(node) = parent.children.remove(name) {
+            parent.children.insert(new_name.to_string(), node);
+        }
+        // any cached balanced-mode table references the old path by name.
+        self.clear_balanced();
+        Ok(())
+    }
+
+    /// Move a node (and its subtree) to a new parent, keeping every
+    /// descendant's `id` unchanged. Fails rather than clobbering an existing
+    /// child of `new_parent` already using the same name, and keeps
+    /// `weight` correct on both sides of the move.
+    pub fn move_node(&mut self, path: &str, new_parent: &str) -> Result<(), CrushError> {
+        if new_parent == path || new_parent.starts_with(&format!("{}/", path)) {
+            return Err(CrushError::InvalidMove {
+                path: path.to_string(),
+                new_parent: new_parent.to_string(),
+            });
+        }
+        let (old_parent_path, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if self.root.get(new_parent).children.contains_key(name) {
+            return Err(CrushError::NameConflict {
+                path: join_path(new_parent, name),
+            });
+        }
+        let node = self.root.get_mut(old_parent_path).children.remove(name);
+        if let Some(node) = node {
+            self.root
+                .get_mut(new_parent)
+                .children
+                .insert(name.to_string(), node);
+            self.root.recompute_weight();
+        }
+        // any cached balanced-mode table references the old path by name.
+        self.clear_balanced();
+        Ok(())
+    }
+
+    /// Set the bytes used on a leaf node.
+    pub fn set_used(&mut self, path: &str, used: u64) {
+        self.root.get_mut(path).used = used;
+    }
+
+    /// Get the bytes used on a leaf node.
+    pub fn get_used(&self, path: &str) -> u64 {
+        self.root.get(path).used
+    }
+
+    /// Total raw capacity of the cluster, in bytes. An alias for `total_weight`
+    /// now that weight is expressed in bytes.
+    pub fn cluster_capacity(&self) -> u64 {
+        self.total_weight()
+    }
+
+    /// Usable free capacity of the cluster, in bytes. A branch's available
+    /// space is clamped to `children * min(child available)`, since a
+    /// replica set can only ever be as large as its most-constrained member.
+    pub fn cluster_available(&self) -> u64 {
+        self.root.available()
+    }
+
+    /// How many bytes each placement group is expected to hold, given the
+    /// recommended number of PGs for `replicas`.
+    pub fn partition_size(&self, replicas: u32) -> u64 {
+        self.cluster_available() / self.get_recommended_pgs(replicas) as u64
+    }
+
     fn get_node_by_path(&self, path: &str) -> &Node {
         self.root.get(path)
     }
 
+    /// Compute a balanced pgid -> osd-paths assignment as an alternative to
+    /// the hash-based `select`, and have `locate_all` consult it from now on.
+    /// Fails, rather than silently under-replicating, if there aren't at
+    /// least `replicas` distinct zones to spread across.
+    ///
+    /// This models Garage's `graph_algo`: conceptually a source feeds each
+    /// partition `replicas` units of flow, each partition can send at most
+    /// one unit into each zone (guaranteeing zone redundancy), and each osd
+    /// has a target of `round(weight / zone_weight * zone_slots)` units
+    /// (its capacity-proportional share of however many slots its own zone
+    /// gets, since zone-redundancy — not raw cluster weight — is what
+    /// actually gates how often a zone is picked) before draining to the
+    /// sink. The graph is filled greedily: an osd's previous assignment is
+    /// kept while its load is below its target (the min-cost augmentation:
+    /// cost 0 to keep, cost 1 to move), and every other slot goes to the osd
+    /// furthest below its target. That fill does not cap an osd at its
+    /// target, so an osd can end up a unit or so above it.
+    pub fn assign_balanced(&mut self, num_pgs: u32, replicas: u32) -> Result<(), CrushError> {
+        let zones = self.zones();
+        if (zones.len() as u32) < replicas {
+            return Err(CrushError::InsufficientRedundancy {
+                domain_type: "zone".to_string(),
+                requested: replicas,
+                available: zones.len() as u32,
+            });
+        }
+
+        // each partition sends exactly one unit into each zone it uses, so a
+        // zone's total share of slots is governed by zone participation
+        // (capped at `num_pgs` per zone, spread evenly across all zones),
+        // not by the zone's weight relative to the whole cluster.
+        let zone_slots = num_pgs as f64 * replicas as f64 / zones.len() as f64;
+
+        let mut target: BTreeMap<String, u64> = BTreeMap::new();
+        for (_, osds) in &zones {
+            let zone_weight = osds.iter().map(|(_, w)| *w).sum::<u64>().max(1) as f64;
+            for (osd, weight) in osds {
+                let tokens = (zone_slots * *weight as f64 / zone_weight).round();
+                target.insert(osd.clone(), tokens as u64);
+            }
+        }
+
+        let previous = self.balanced_table.clone();
+        let mut load: BTreeMap<String, u64> = target.keys().map(|k| (k.clone(), 0)).collect();
+        let mut table: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+
+        for pg in 1..=num_pgs {
+            let mut zones_used: Vec<String> = Vec::new();
+            let mut picked: Vec<String> = Vec::new();
+
+            // keep whatever was assigned before when it's still within
+            // budget: these are the cost-0 edges of the min-cost augmentation.
+            if let Some(prev) = previous.as_ref().and_then(|p| p.get(&pg)) {
+                for osd in prev {
+                    if picked.len() as u32 >= replicas {
+                        break;
+                    }
+                    let zone = zone_of(osd);
+                    if zones_used.contains(&zone) || !target.contains_key(osd) {
+                        continue;
+                    }
+                    if load[osd] < target[osd].max(1) {
+                        zones_used.push(zone);
+                        picked.push(osd.clone());
+                    }
+                }
+            }
+
+            // fill any remaining slots from zones not yet used by this
+            // partition, picking the osd furthest below its token target.
+            // Zones are re-ranked, least-loaded-relative-to-target first, on
+            // every partition rather than walked in a fixed order, so that
+            // every zone actually gets to participate instead of only ever
+            // the first `replicas` zones in BTreeMap order.
+            let mut zone_order: Vec<&(String, Vec<(String, u64)>)> = zones.iter().collect();
+            zone_order.sort_by_key(|(_, osds)| {
+                let load_sum: i64 = osds.iter().map(|(osd, _)| load[osd] as i64).sum();
+                let target_sum: i64 = osds.iter().map(|(osd, _)| target[osd] as i64).sum();
+                load_sum - target_sum
+            });
+
+            for (zone_name, osds) in zone_order {
+                if picked.len() as u32 >= replicas {
+                    break;
+                }
+                if zones_used.contains(zone_name) {
+                    continue;
+                }
+                let candidate = osds
+                    .iter()
+                    .filter(|(osd, _)| !picked.contains(osd))
+                    .min_by_key(|(osd, _)| load[osd] as i64 - target[osd] as i64);
+                if let Some((osd, _)) = candidate {
+                    zones_used.push(zone_name.clone());
+                    picked.push(osd.clone());
+                }
+            }
+
+            for osd in &picked {
+                *load.get_mut(osd).unwrap() += 1;
+            }
+            table.insert(pg, picked);
+        }
+
+        self.balanced_table = Some(table);
+        Ok(())
+    }
+
+    /// Drop the balanced-mode table, reverting `locate_all` to straw2 hashing.
+    pub fn clear_balanced(&mut self) {
+        self.balanced_table = None;
+    }
+
+    /// The cluster's top-level children, each with the flattened `(osd path,
+    /// weight)` pairs beneath it. Used as the zone/osd layer of `assign_balanced`.
+    fn zones(&self) -> Vec<(String, Vec<(String, u64)>)> {
+        self.root
+            .children
+            .iter()
+            .filter(|(_, node)| !node.out)
+            .map(|(zone_name, zone_node)| (zone_name.clone(), collect_osds(zone_name, zone_node)))
+            .collect()
+    }
+
+    /// Stage a weight change. It has no effect until `commit` is called.
+    pub fn stage_add_weight(&mut self, path: &str, weight: i64) {
+        self.staged.push(StagedOp::AddWeight {
+            path: path.to_string(),
+            weight,
+        });
+    }
+
+    /// Stage an IN/OUT flip. It has no effect until `commit` is called.
+    pub fn stage_set_inout(&mut self, path: &str, out: bool) {
+        self.staged.push(StagedOp::SetInout {
+            path: path.to_string(),
+            out,
+        });
+    }
+
+    /// Preview the effect of the currently staged operations by diffing
+    /// `locate_all` for `num_pgs` placement groups before and after they are
+    /// applied, the same comparison the `move_factor_add` test does by hand.
+    pub fn preview(&self, num_pgs: u32, replicas: u32) -> MovementReport {
+        let mut after = self.apply_staged();
+        // commit drops any balanced-mode table, so the post-commit side
+        // places through the hash-based `select`.
+        after.clear_balanced();
+        let mut moved = Vec::new();
+        for pg in 1..=num_pgs {
+            if self.locate_all(pg, replicas) != after.locate_all(pg, replicas) {
+                moved.push(pg);
+            }
+        }
+        let percent_moved = moved.len() as f64 / num_pgs as f64 * 100.0;
+        MovementReport {
+            percent_moved,
+            moved,
+        }
+    }
+
+    /// Apply the staged operations to the committed map and bump `version`.
+    pub fn commit(&mut self) {
+        let after = self.apply_staged();
+        self.root = after.root;
+        // the staged ops may have minted ids for newly-created nodes; carry
+        // the advanced counter over so a later direct add_weight doesn't
+        // reuse one of them.
+        self.next_id = after.next_id;
+        self.staged.clear();
+        self.version += 1;
+        // staged ops may have added/removed/reweighted nodes; any cached
+        // balanced-mode table was computed against the pre-commit topology.
+        self.clear_balanced();
+    }
+
+    /// Discard the staged operations, leaving the committed map untouched.
+    pub fn revert(&mut self) {
+        self.staged.clear();
+    }
+
+    /// The version of the committed map. Starts at 0 and increments on every `commit`.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Serialize the committed map (including its `version`) so it can be
+    /// saved to disk or shipped over RPC. Staged-but-uncommitted operations
+    /// are not included.
+    pub fn to_bytes(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec(self)
+    }
+
+    /// Deserialize a map produced by `to_bytes`. Because `select`/`locate`
+    /// are deterministic given an identical tree, a map restored this way on
+    /// another process produces byte-identical placements.
+    pub fn from_bytes(bytes: &[u8]) -> serde_json::Result<Self> {
+        serde_json::from_slice(bytes)
+    }
+
+    /// Clone the committed map with the staged operations applied.
+    fn apply_staged(&self) -> Crush {
+        let mut after = self.clone();
+        for op in &self.staged {
+            match op {
+                StagedOp::AddWeight { path, weight } => {
+                    after.root.add_weight(path, *weight, &mut after.next_id)
+                }
+                StagedOp::SetInout { path, out } => after.root.get_mut(path).out = *out,
+            }
+        }
+        after
+    }
+
     /// Select `num` targets accoding to `pgid`.
     pub fn select(&self, pgid: u32, num: u32, start_path: &str) -> Vec<String> {
         let mut targets = Vec::<String>::new();
@@ -153,18 +539,121 @@ impl Crush {
         }
         targets
     }
+
+    /// Like `locate_all`, but guarantees the returned `osd` paths are spread
+    /// across at least `replicas` distinct ancestors of type `domain_type`
+    /// (e.g. `"rack"`), instead of leaving that to whichever level the
+    /// caller happens to recurse through by hand. Fails explicitly rather
+    /// than looping forever when the topology can't satisfy the request.
+    pub fn select_with_failure_domain(
+        &self,
+        pgid: u32,
+        replicas: u32,
+        domain_type: &str,
+    ) -> Result<Vec<String>, CrushError> {
+        let domain_path = self.domain_start_path(domain_type)?;
+        let domains = self.get_node_by_path(&domain_path);
+        let available = domains.children.values().filter(|c| !c.out).count() as u32;
+        if available < replicas {
+            return Err(CrushError::InsufficientRedundancy {
+                domain_type: domain_type.to_string(),
+                requested: replicas,
+                available,
+            });
+        }
+
+        let mut targets = Vec::with_capacity(replicas as usize);
+        for domain in self.select(pgid, replicas, &domain_path) {
+            let mut path = if domain_path.is_empty() {
+                domain
+            } else {
+                format!("{}/{}", domain_path, domain)
+            };
+            while !path.contains("osd") {
+                let select = self.select(pgid, 1, &path).into_iter().next().unwrap();
+                path = format!("{}/{}", path, select);
+            }
+            targets.push(path);
+        }
+        Ok(targets)
+    }
+
+    /// Walk down the first child at each level until reaching the level
+    /// whose nodes are of type `domain_type`, and return the path to get
+    /// there. Errors instead of silently bottoming out at the leaves if
+    /// `domain_type` is never observed anywhere in the tree.
+    fn domain_start_path(&self, domain_type: &str) -> Result<String, CrushError> {
+        let mut path = String::new();
+        let mut node = &self.root;
+        loop {
+            match node.children.keys().next() {
+                Some(name) if type_of(name) == domain_type => break,
+                Some(name) => {
+                    path = join_path(&path, name);
+                    node = &node.children[name];
+                }
+                None => {
+                    return Err(CrushError::UnknownDomainType {
+                        domain_type: domain_type.to_string(),
+                    });
+                }
+            }
+        }
+        Ok(path)
+    }
+}
+
+/// Infer a node's type from its path component, e.g. `"rack.1"` -> `"rack"`.
+fn type_of(name: &str) -> &str {
+    name.split('.').next().unwrap_or(name)
+}
+
+/// Join a parent path and a child name, treating an empty parent as root.
+fn join_path(parent: &str, child: &str) -> String {
+    if parent.is_empty() {
+        child.to_string()
+    } else {
+        format!("{}/{}", parent, child)
+    }
+}
+
+/// The top-level zone component of a full osd path, e.g.
+/// `"rack.1/host.2/osd.3"` -> `"rack.1"`.
+fn zone_of(path: &str) -> String {
+    path.split_once('/')
+        .map(|(zone, _)| zone.to_string())
+        .unwrap_or_else(|| path.to_string())
+}
+
+/// Flatten every `osd` leaf beneath `node` into `(full path, weight)` pairs.
+fn collect_osds(prefix: &str, node: &Node) -> Vec<(String, u64)> {
+    if node.children.is_empty() {
+        return vec![(prefix.to_string(), node.weight)];
+    }
+    node.children
+        .iter()
+        .filter(|(_, child)| !child.out)
+        .flat_map(|(name, child)| collect_osds(&format!("{}/{}", prefix, name), child))
+        .collect()
 }
 
 impl Node {
-    /// Add weight to a node.
-    fn add_weight(&mut self, path: &str, weight: i64) {
+    /// Add weight to a node, assigning a fresh, stable `id` to any node
+    /// created along the way.
+    fn add_weight(&mut self, path: &str, weight: i64, next_id: &mut u64) {
         self.weight = (self.weight as i64 + weight) as u64;
         if path.is_empty() {
             return;
         }
         let (name, suffix) = path.split_once('/').unwrap_or((path, ""));
-        let child = self.children.entry(name.into()).or_default();
-        child.add_weight(suffix, weight);
+        let child = self.children.entry(name.into()).or_insert_with(|| {
+            *next_id += 1;
+            Node {
+                id: *next_id,
+                ..Default::default()
+            }
+        });
+        child.add_weight(suffix, weight, next_id);
     }
 
     /// Get a node by path.
@@ -185,13 +674,46 @@ impl Node {
         self.children.get_mut(name).unwrap().get_mut(suffix)
     }
 
-    /// Choose a child according to key and index.
+    /// Re-derive `weight` bottom-up from the leaves. Needed after structural
+    /// edits like `move_node` that relocate a subtree without going through
+    /// `add_weight`, so branch weights don't go stale (and `choose` doesn't
+    /// route into a now-empty branch that still reports nonzero weight).
+    fn recompute_weight(&mut self) -> u64 {
+        if !self.children.is_empty() {
+            self.weight = self
+                .children
+                .values_mut()
+                .map(|child| child.recompute_weight())
+                .sum();
+        }
+        self.weight
+    }
+
+    /// Usable free bytes under this node. A leaf is `weight - used`; a
+    /// branch is clamped to its least-available child times its child count,
+    /// since a replica set is limited by its smallest member.
+    fn available(&self) -> u64 {
+        if self.children.is_empty() {
+            return self.weight.saturating_sub(self.used);
+        }
+        let min_child_available = self
+            .children
+            .values()
+            .map(|c| c.available())
+            .min()
+            .unwrap_or(0);
+        min_child_available * self.children.len() as u64
+    }
+
+    /// Choose a child according to key and index. Hashes on the child's
+    /// stable `id` rather than its path component, so renaming a node or
+    /// moving it elsewhere in the tree doesn't change placement.
     fn choose(&self, key: u32, index: u32) -> &str {
         self.children
             .iter()
             .map(|(name, child)| {
                 let mut hasher = DefaultHasher::new();
-                name.hash(&mut hasher);
+                child.id.hash(&mut hasher);
                 key.hash(&mut hasher);
                 index.hash(&mut hasher);
 
@@ -426,6 +948,497 @@ mod tests {
         assert!(moved_percentage < 80.0);
     }
 
+    #[test]
+    fn stage_preview_commit_revert() {
+        // staging a new host should preview the same kind of movement that
+        // move_factor_add observes by manually cloning the map, but through
+        // the staging API instead.
+        let hosts = 3;
+        let osds = 5;
+
+        let num_of_pgs = 16_384;
+        let replicas = 3;
+
+        let mut c = build_ha_cluster(hosts, osds);
+        assert_eq!(c.version(), 0);
+
+        for i in 1..=osds {
+            c.stage_add_weight(&format!("host.4/osd.{}", i), 1);
+        }
+
+        let report = c.preview(num_of_pgs, replicas);
+        assert!(report.percent_moved > 0.0 && report.percent_moved < 80.0);
+        assert_eq!(
+            report.moved.len() as f64,
+            report.percent_moved / 100.0 * num_of_pgs as f64
+        );
+
+        // reverting should leave the committed map untouched.
+        let total_before = c.total_weight();
+        c.revert();
+        assert_eq!(c.total_weight(), total_before);
+        assert_eq!(c.version(), 0);
+
+        for i in 1..=osds {
+            c.stage_add_weight(&format!("host.4/osd.{}", i), 1);
+        }
+        c.commit();
+        assert_eq!(c.get_weight("host.4/osd.1"), 1);
+        assert_eq!(c.version(), 1);
+    }
+
+    #[test]
+    fn stage_set_inout_is_previewed_and_committed() {
+        let hosts = 3;
+        let osds = 5;
+
+        let num_of_pgs = 16_384;
+        let replicas = 3;
+
+        let mut c = build_ha_cluster(hosts, osds);
+        assert!(!c.get_inout("host.1"));
+
+        // taking a whole host out should be staged, previewable, and have
+        // no effect on the committed map until commit() is called.
+        c.stage_set_inout("host.1", true);
+
+        let report = c.preview(num_of_pgs, replicas);
+        assert!(report.percent_moved > 0.0 && report.percent_moved < 80.0);
+        assert!(!c.get_inout("host.1"));
+
+        c.commit();
+        assert!(c.get_inout("host.1"));
+    }
+
+    #[test]
+    fn clear_balanced_reverts_locate_all_to_hash_based_selection() {
+        let hosts = 3;
+        let osds = 5;
+        let replicas = 3;
+        let num_of_pgs = 1_024;
+
+        let mut c = build_ha_cluster(hosts, osds);
+        let hashed: Vec<Vec<String>> = (1..=num_of_pgs)
+            .map(|pg| c.locate_all(pg, replicas))
+            .collect();
+
+        // switching into balanced mode and back should round-trip: once
+        // cleared, locate_all must go back to the same hash-based
+        // placements it produced before assign_balanced was ever called.
+        c.assign_balanced(num_of_pgs, replicas).unwrap();
+        c.clear_balanced();
+        let after_clear: Vec<Vec<String>> = (1..=num_of_pgs)
+            .map(|pg| c.locate_all(pg, replicas))
+            .collect();
+        assert_eq!(hashed, after_clear);
+    }
+
+    #[test]
+    fn serde_round_trip_is_byte_identical() {
+        let racks = 3;
+        let hosts = 3;
+        let osds = 10;
+
+        let mut c = build_datacenter_cluster(racks, hosts, osds);
+        c.set_inout("rack.1/host.1/osd.1", true);
+        c.commit();
+
+        let bytes = c.to_bytes().unwrap();
+        let restored = Crush::from_bytes(&bytes).unwrap();
+
+        assert_eq!(restored.version(), c.version());
+        assert_eq!(restored.total_weight(), c.total_weight());
+
+        for pg in 1..=100 {
+            assert_eq!(c.locate_all(pg, 3), restored.locate_all(pg, 3));
+        }
+    }
+
+    #[test]
+    fn select_with_failure_domain_spreads_across_racks() {
+        let racks = 3;
+        let hosts = 3;
+        let osds = 10;
+
+        let c = build_datacenter_cluster(racks, hosts, osds);
+
+        for pg in 1..=1_000 {
+            let osds = c.select_with_failure_domain(pg, 3, "rack").unwrap();
+            assert_eq!(osds.len(), 3);
+
+            let racks: HashSet<String> = osds
+                .iter()
+                .map(|path| path.split_once('/').unwrap().0.to_string())
+                .collect();
+            assert_eq!(racks.len(), 3);
+        }
+    }
+
+    #[test]
+    fn select_with_failure_domain_fails_when_not_enough_domains() {
+        // only two racks, but three replicas requested
+        let c = build_datacenter_cluster(2, 3, 10);
+
+        let err = c.select_with_failure_domain(1, 3, "rack").unwrap_err();
+        assert_eq!(
+            err,
+            CrushError::InsufficientRedundancy {
+                domain_type: "rack".to_string(),
+                requested: 3,
+                available: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn select_with_failure_domain_fails_for_unknown_domain_type() {
+        let c = build_datacenter_cluster(3, 3, 10);
+
+        let err = c
+            .select_with_failure_domain(1, 3, "bogus_domain")
+            .unwrap_err();
+        assert_eq!(
+            err,
+            CrushError::UnknownDomainType {
+                domain_type: "bogus_domain".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn capacity_and_available_are_clamped_by_constrained_member() {
+        // a 2-host, 2-osd-per-host cluster where each osd is a 100-byte disk
+        let mut c = build_ha_cluster(2, 2);
+        c.add_weight("host.1/osd.1", 99); // 1 + 99 = 100 bytes of capacity
+        c.add_weight("host.1/osd.2", 99);
+        c.add_weight("host.2/osd.1", 99);
+        c.add_weight("host.2/osd.2", 99);
+
+        assert_eq!(c.cluster_capacity(), 400);
+        assert_eq!(c.cluster_available(), 400);
+
+        // fill up one disk; the whole cluster's reported availability should
+        // drop by more than that single disk, because both hosts and both
+        // disks per host are clamped by their most-constrained member.
+        c.set_used("host.1/osd.1", 90);
+        assert_eq!(c.get_used("host.1/osd.1"), 90);
+        assert_eq!(c.cluster_available(), 2 * (2 * 10));
+    }
+
+    #[test]
+    fn partition_size_divides_available_by_recommended_pgs() {
+        let racks = 5;
+        let hosts = 5;
+        let osds = 8;
+
+        let mut c = build_datacenter_cluster(racks, hosts, osds);
+        for rack in 1..=racks {
+            for host in 1..=hosts {
+                for osd in 1..=osds {
+                    c.add_weight(&format!("rack.{}/host.{}/osd.{}", rack, host, osd), 99);
+                }
+            }
+        }
+
+        let replicas = 3;
+        assert_eq!(
+            c.partition_size(replicas),
+            c.cluster_available() / c.get_recommended_pgs(replicas) as u64
+        );
+    }
+
+    #[test]
+    fn assign_balanced_gives_every_pg_distinct_osds_in_distinct_zones() {
+        let racks = 3;
+        let hosts = 3;
+        let osds = 5;
+
+        let num_of_pgs = 1_024;
+        let replicas = 3;
+
+        let mut c = build_datacenter_cluster(racks, hosts, osds);
+        c.assign_balanced(num_of_pgs, replicas).unwrap();
+
+        for pg in 1..=num_of_pgs {
+            let placement = c.locate_all(pg, replicas);
+            assert_eq!(placement.len(), replicas as usize);
+
+            let distinct_osds: HashSet<&String> = placement.iter().collect();
+            assert_eq!(distinct_osds.len(), replicas as usize);
+
+            let distinct_racks: HashSet<String> = placement
+                .iter()
+                .map(|p| p.split_once('/').unwrap().0.to_string())
+                .collect();
+            assert_eq!(distinct_racks.len(), replicas as usize);
+        }
+    }
+
+    #[test]
+    fn assign_balanced_keeps_load_within_one_token_of_target() {
+        let hosts = 4;
+        let osds = 5;
+
+        let num_of_pgs = 4_096;
+        let replicas = 3;
+
+        let mut c = build_ha_cluster(hosts, osds);
+        c.assign_balanced(num_of_pgs, replicas).unwrap();
+
+        let mut load: HashMap<String, u64> = HashMap::new();
+        for pg in 1..=num_of_pgs {
+            for osd in c.locate_all(pg, replicas) {
+                *load.entry(osd).or_insert(0) += 1;
+            }
+        }
+
+        let target = num_of_pgs as f64 * replicas as f64 / (hosts * osds) as f64;
+        for (_, count) in load {
+            assert!((count as f64 - target).abs() <= 1.0);
+        }
+    }
+
+    #[test]
+    fn assign_balanced_minimizes_movement_on_topology_change() {
+        // same scenario as move_factor_add, but using balanced mode: adding
+        // a fourth host should move far fewer pgs than straw2 hashing does.
+        let hosts = 3;
+        let osds = 5;
+
+        let num_of_pgs = 4_096;
+        let replicas = 3;
+
+        let mut before = build_ha_cluster(hosts, osds);
+        before.assign_balanced(num_of_pgs, replicas).unwrap();
+
+        let mut after = before.clone();
+        for i in 1..=osds {
+            after.add_weight(&format!("host.4/osd.{}", i), 1);
+        }
+        after.assign_balanced(num_of_pgs, replicas).unwrap();
+
+        let mut moved = 0;
+        for pg in 1..=num_of_pgs {
+            if before.locate_all(pg, replicas) != after.locate_all(pg, replicas) {
+                moved += 1;
+            }
+        }
+        let moved_percentage = moved as f64 / num_of_pgs as f64 * 100.0;
+        assert!(moved_percentage < 80.0);
+    }
+
+    #[test]
+    fn assign_balanced_keeps_load_within_one_token_of_target_with_heterogeneous_weights() {
+        // 3 zones, each with two osds of very different weight, so the
+        // target a naive whole-cluster weight proportion would compute
+        // (e.g. for the 50/50 zone) doesn't match what the zone-redundancy
+        // constraint (exactly one pick per zone per partition) actually
+        // demands of it.
+        let mut c = Crush::default();
+        c.add_weight("zone.1/osd.a", 100);
+        c.add_weight("zone.1/osd.b", 1);
+        c.add_weight("zone.2/osd.a", 50);
+        c.add_weight("zone.2/osd.b", 50);
+        c.add_weight("zone.3/osd.a", 1);
+        c.add_weight("zone.3/osd.b", 100);
+
+        let num_of_pgs = 2_048;
+        let replicas = 3;
+        c.assign_balanced(num_of_pgs, replicas).unwrap();
+
+        let mut load: HashMap<String, u64> = HashMap::new();
+        for pg in 1..=num_of_pgs {
+            for osd in c.locate_all(pg, replicas) {
+                *load.entry(osd).or_insert(0) += 1;
+            }
+        }
+
+        // each zone is picked exactly once per partition (3 zones, 3
+        // replicas), so each zone's two osds should split that zone's
+        // num_of_pgs slots in proportion to their own weight.
+        let zone_target = |weight: f64, zone_weight: f64| num_of_pgs as f64 * weight / zone_weight;
+        let within_one_token = |osd: &str, target: f64| (load[osd] as f64 - target).abs() <= 1.0;
+        assert!(within_one_token("zone.1/osd.a", zone_target(100.0, 101.0)));
+        assert!(within_one_token("zone.1/osd.b", zone_target(1.0, 101.0)));
+        assert!(within_one_token("zone.2/osd.a", zone_target(50.0, 100.0)));
+        assert!(within_one_token("zone.2/osd.b", zone_target(50.0, 100.0)));
+        assert!(within_one_token("zone.3/osd.a", zone_target(1.0, 101.0)));
+        assert!(within_one_token("zone.3/osd.b", zone_target(100.0, 101.0)));
+    }
+
+    #[test]
+    fn assign_balanced_fails_when_fewer_zones_than_replicas() {
+        // 2 hosts (zones), but 3 replicas requested: no assignment can
+        // honor zone redundancy, so this must error instead of silently
+        // handing back under-replicated placements.
+        let mut c = build_ha_cluster(2, 5);
+
+        let err = c.assign_balanced(1_024, 3).unwrap_err();
+        assert_eq!(
+            err,
+            CrushError::InsufficientRedundancy {
+                domain_type: "zone".to_string(),
+                requested: 3,
+                available: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn balanced_table_is_invalidated_by_topology_mutation() {
+        // once a rename/move/commit changes the tree, a cached
+        // pgid -> path table referencing the old paths must not survive to
+        // be handed back (or worse, looked up and panic).
+        let mut c = build_ha_cluster(3, 5);
+        c.assign_balanced(1_024, 3).unwrap();
+
+        c.rename("host.3", "host.web").unwrap();
+        for pg in 1..=1_024 {
+            for osd in c.locate_all(pg, 3) {
+                // every returned path must still resolve in the live tree.
+                c.get_weight(&osd);
+            }
+        }
+    }
+
+    #[test]
+    fn rename_preserves_placement() {
+        let hosts = 3;
+        let osds = 5;
+
+        let num_of_pgs = 1_024;
+        let replicas = 3;
+
+        let mut before = build_ha_cluster(hosts, osds);
+        let before_placements: Vec<Vec<String>> = (1..=num_of_pgs)
+            .map(|pg| before.locate_all(pg, replicas))
+            .collect();
+
+        // rename host.3 to host.web, which changes the path but should not
+        // change which device any placement group lands on.
+        before.rename("host.3", "host.web").unwrap();
+
+        let after_placements: Vec<Vec<String>> = (1..=num_of_pgs)
+            .map(|pg| {
+                before
+                    .locate_all(pg, replicas)
+                    .into_iter()
+                    .map(|p| p.replace("host.web", "host.3"))
+                    .collect()
+            })
+            .collect();
+
+        assert_eq!(before_placements, after_placements);
+    }
+
+    #[test]
+    fn move_node_preserves_subtree_reparents_and_fixes_up_weight() {
+        // two racks with non-colliding host names, so the move below has
+        // somewhere conflict-free to land.
+        let mut c = Crush::default();
+        for osd in 1..=5 {
+            c.add_weight(&format!("rack.1/host.a/osd.{}", osd), 1);
+            c.add_weight(&format!("rack.1/host.b/osd.{}", osd), 1);
+            c.add_weight(&format!("rack.2/host.c/osd.{}", osd), 1);
+        }
+        let weight_before = c.get_weight("rack.1/host.a");
+
+        c.move_node("rack.1/host.a", "rack.2").unwrap();
+
+        // it's gone from rack.1 and shows up, with its subtree intact, under rack.2.
+        assert_eq!(c.get_osds(&c.root), 15);
+        assert_eq!(c.get_weight("rack.2/host.a"), weight_before);
+        assert_eq!(c.get_weight("rack.2/host.a/osd.1"), 1);
+
+        // ancestor weights on both sides of the move are re-derived, not stale.
+        assert_eq!(c.get_weight("rack.1"), 5);
+        assert_eq!(c.get_weight("rack.2"), 10);
+        assert_eq!(c.total_weight(), 15);
+
+        // the now-emptied-of-host.a-but-still-populated rack.1 is still
+        // selectable without panicking.
+        for pg in 1..=100 {
+            c.locate_all(pg, 1);
+        }
+    }
+
+    #[test]
+    fn move_node_rejects_name_collision() {
+        // build_datacenter_cluster gives every rack a host.1/host.2, so
+        // moving rack.1/host.1 into rack.2 would collide.
+        let mut c = build_datacenter_cluster(2, 2, 5);
+        let osds_before = c.get_osds(&c.root);
+        let rack1_weight_before = c.get_weight("rack.1");
+
+        let err = c.move_node("rack.1/host.1", "rack.2").unwrap_err();
+        assert_eq!(
+            err,
+            CrushError::NameConflict {
+                path: "rack.2/host.1".to_string()
+            }
+        );
+
+        // nothing was mutated.
+        assert_eq!(c.get_osds(&c.root), osds_before);
+        assert_eq!(c.get_weight("rack.1"), rack1_weight_before);
+        assert_eq!(c.get_weight("rack.2/host.1/osd.1"), 1);
+    }
+
+    #[test]
+    fn move_node_rejects_move_into_own_descendant() {
+        let mut c = build_datacenter_cluster(2, 2, 5);
+        let osds_before = c.get_osds(&c.root);
+        let rack1_weight_before = c.get_weight("rack.1");
+
+        let err = c.move_node("rack.1", "rack.1/host.1").unwrap_err();
+        assert_eq!(
+            err,
+            CrushError::InvalidMove {
+                path: "rack.1".to_string(),
+                new_parent: "rack.1/host.1".to_string(),
+            }
+        );
+
+        // nothing was mutated.
+        assert_eq!(c.get_osds(&c.root), osds_before);
+        assert_eq!(c.get_weight("rack.1"), rack1_weight_before);
+    }
+
+    #[test]
+    fn rename_rejects_name_collision() {
+        let mut c = build_ha_cluster(3, 5);
+        let osds_before = c.get_osds(&c.root);
+
+        let err = c.rename("host.1", "host.2").unwrap_err();
+        assert_eq!(
+            err,
+            CrushError::NameConflict {
+                path: "host.2".to_string()
+            }
+        );
+
+        // both the renamed-from and the would-be-clobbered node survive.
+        assert_eq!(c.get_osds(&c.root), osds_before);
+        assert_eq!(c.get_weight("host.1/osd.1"), 1);
+        assert_eq!(c.get_weight("host.2/osd.1"), 1);
+    }
+
+    #[test]
+    fn commit_advances_next_id_after_staged_inserts() {
+        // stage+commit a new host, then add another directly: both should
+        // mint distinct, never-reused ids.
+        let mut c = build_ha_cluster(3, 5);
+        for i in 1..=5 {
+            c.stage_add_weight(&format!("host.4/osd.{}", i), 1);
+        }
+        c.commit();
+
+        c.add_weight("host.5/osd.1", 1);
+
+        assert_ne!(c.root.get("host.4").id, c.root.get("host.5").id);
+        assert_ne!(c.root.get("host.4/osd.1").id, c.root.get("host.5/osd.1").id);
+    }
+
     #[test]
     fn recommended_pgs() {
         // does the recommended number of PGs match the example formula in the ceph documentation?